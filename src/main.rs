@@ -1,9 +1,15 @@
-use libp2p::swarm::SwarmEvent;
+use libp2p::swarm::{behaviour::toggle::Toggle, SwarmEvent};
 use libp2p::tcp::GenTcpConfig;
 use libp2p::{
+    autonat,
     core::upgrade,
-    floodsub::{Floodsub, FloodsubEvent, Topic},
+    floodsub::{Floodsub, FloodsubEvent, FloodsubMessage, Topic},
     futures::StreamExt,
+    gossipsub::{
+        error::PublishError, Gossipsub, GossipsubConfig, GossipsubEvent, GossipsubMessage,
+        IdentTopic, MessageAuthenticity,
+    },
+    identify::{Identify, IdentifyConfig, IdentifyEvent},
     identity,
     mdns::{Mdns, MdnsEvent},
     mplex,
@@ -11,16 +17,58 @@ use libp2p::{
     ping,
     swarm::{Swarm, SwarmBuilder},
     tcp::TokioTcpTransport,
-    NetworkBehaviour, PeerId, Transport,
+    Multiaddr, NetworkBehaviour, PeerId, Transport,
 };
 use log::{error, info};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
-use tokio::{fs, io::AsyncBufReadExt, sync::mpsc};
-use crate::ping::PingConfig;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::{
+    fs,
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, Lines},
+    sync::{mpsc, Notify},
+};
 
 const STORAGE_FILE_PATH: &str = "./stories.json";
+const TOPICS_FILE_PATH: &str = "./topics.json";
+const ALIASES_FILE_PATH: &str = "./aliases.json";
+const NICKNAMES_FILE_PATH: &str = "./nicknames.json";
+const DEFAULT_ALIASES: &[(&str, &str)] = &[("l", "ls s"), ("lp", "ls p"), ("q", "quit")];
+// every command `handle_input` dispatches, matched on a word boundary; their
+// first words are the built-ins, which aliases may not shadow
+const COMMANDS: &[(&str, Command)] = &[
+    ("ls p", Command::ListPeers),
+    ("ls s", Command::ListStories),
+    ("ls t", Command::ListTopics),
+    ("reachability", Command::Reachability),
+    ("diagnose", Command::Diagnose),
+    ("db check", Command::DbCheck),
+    ("fingerprint", Command::Fingerprint),
+    ("ping", Command::Ping),
+    ("request story", Command::RequestStory),
+    ("create s", Command::CreateStory),
+    ("publish s", Command::PublishStory),
+    ("fav s", Command::Favorite),
+    ("unfav s", Command::Unfavorite),
+    ("sub t", Command::Subscribe),
+    ("unsub t", Command::Unsubscribe),
+    ("pub t", Command::PublishToTopic),
+    ("nick", Command::Nick),
+    ("alias", Command::Alias),
+    ("quit", Command::Quit),
+];
+const DEFAULT_PAGE_SIZE: usize = 20;
+const PROTOCOL_VERSION: &str = "/p2p-play/0.1.0";
+// floodsub drops incoming RPCs over 2048 bytes, leave room for the envelope
+const MAX_BROADCAST_SIZE: usize = 1800;
+const SCRIPT_PEER_WAIT: Duration = Duration::from_secs(5);
+const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync + 'static>>;
 type Stories = Vec<Story>;
@@ -28,6 +76,66 @@ type Stories = Vec<Story>;
 static KEYS: Lazy<identity::Keypair> = Lazy::new(identity::Keypair::generate_ed25519);
 static PEER_ID: Lazy<PeerId> = Lazy::new(|| PeerId::from(KEYS.public()));
 static TOPIC: Lazy<Topic> = Lazy::new(|| Topic::new("stories"));
+static DEFAULT_PUBLIC: Lazy<bool> = Lazy::new(|| match env::var("P2P_PLAY_DEFAULT_VISIBILITY") {
+    Ok(v) if v.trim().eq_ignore_ascii_case("public") => true,
+    Ok(v) if v.trim().eq_ignore_ascii_case("private") => false,
+    Ok(v) => {
+        error!(
+            "unknown P2P_PLAY_DEFAULT_VISIBILITY {:?}, new stories will be private",
+            v
+        );
+        false
+    }
+    Err(_) => false,
+});
+static PUBSUB_KIND: Lazy<PubsubKind> = Lazy::new(|| match env::var("P2P_PLAY_PUBSUB") {
+    Ok(v) if v.eq_ignore_ascii_case("floodsub") => PubsubKind::Floodsub,
+    Ok(v) if v.eq_ignore_ascii_case("gossipsub") => PubsubKind::Gossipsub,
+    Ok(v) => {
+        error!("unknown P2P_PLAY_PUBSUB {:?}, using floodsub", v);
+        PubsubKind::Floodsub
+    }
+    Err(_) => PubsubKind::Floodsub,
+});
+// the defaults plus the aliases file, loaded once at startup and kept in step with it by `alias`
+static ALIASES: Lazy<Mutex<HashMap<String, String>>> = Lazy::new(|| {
+    Mutex::new(
+        DEFAULT_ALIASES
+            .iter()
+            .map(|(name, cmd)| (name.to_string(), cmd.to_string()))
+            .collect(),
+    )
+});
+// loaded once at startup and kept in step with the file by `nick`
+static NICKNAMES: Lazy<Mutex<HashMap<String, String>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static PENDING_PINGS: Lazy<Mutex<HashSet<PeerId>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+static PEER_KEYS: Lazy<Mutex<HashMap<PeerId, identity::PublicKey>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+static STORAGE_PATH: Lazy<String> =
+    Lazy::new(|| env::var("P2P_PLAY_DB_PATH").unwrap_or_else(|_| STORAGE_FILE_PATH.to_owned()));
+static TOPICS_PATH: Lazy<String> =
+    Lazy::new(|| env::var("P2P_PLAY_TOPICS_PATH").unwrap_or_else(|_| TOPICS_FILE_PATH.to_owned()));
+static ALIASES_PATH: Lazy<String> = Lazy::new(|| {
+    env::var("P2P_PLAY_ALIASES_PATH").unwrap_or_else(|_| ALIASES_FILE_PATH.to_owned())
+});
+static NICKNAMES_PATH: Lazy<String> = Lazy::new(|| {
+    env::var("P2P_PLAY_NICKNAMES_PATH").unwrap_or_else(|_| NICKNAMES_FILE_PATH.to_owned())
+});
+static RECORD_EVENTS_PATH: Lazy<Option<String>> =
+    Lazy::new(|| env::var("P2P_PLAY_RECORD_EVENTS").ok());
+static PAGE_SIZE: Lazy<usize> = Lazy::new(|| match env::var("P2P_PLAY_PAGE_SIZE") {
+    Ok(v) => match v.trim().parse::<usize>() {
+        Ok(size) if size > 0 => size,
+        _ => {
+            error!(
+                "invalid P2P_PLAY_PAGE_SIZE {:?}, using {}",
+                v, DEFAULT_PAGE_SIZE
+            );
+            DEFAULT_PAGE_SIZE
+        }
+    },
+    Err(_) => DEFAULT_PAGE_SIZE,
+});
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Story {
@@ -36,12 +144,73 @@ struct Story {
     header: String,
     body: String,
     public: bool,
+    #[serde(default)]
+    favorite: bool,
+    #[serde(default)]
+    reply_to: Option<usize>,
+    // only set on stories sent to peers, where the parent may be in another chunk or private
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reply_to_title: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq)]
+enum DbCheck {
+    Missing,
+    Healthy(usize),
+    Problems(Vec<String>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PubsubKind {
+    Floodsub,
+    Gossipsub,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum SortMode {
+    Oldest,
+    Newest,
+    Title,
+}
+
+#[derive(Debug)]
+struct ListOptions {
+    page: usize,
+    size: usize,
+    sort: SortMode,
+}
+
+// a command from the `COMMANDS` table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    ListPeers,
+    ListStories,
+    ListTopics,
+    Reachability,
+    Diagnose,
+    DbCheck,
+    Fingerprint,
+    Ping,
+    RequestStory,
+    CreateStory,
+    PublishStory,
+    Favorite,
+    Unfavorite,
+    Subscribe,
+    Unsubscribe,
+    PublishToTopic,
+    Nick,
+    Alias,
+    Quit,
+}
+
+// variant names are part of the wire format, so ALL keeps its spelling
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 enum ListMode {
     ALL,
     One(String),
+    Title(String),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -56,23 +225,60 @@ struct ListResponse {
     receiver: String,
 }
 
+#[derive(Debug)]
+struct PubsubMessage {
+    source: PeerId,
+    topics: Vec<String>,
+    data: Vec<u8>,
+}
+
 enum EventType {
     Response(ListResponse),
     Input(String),
-    FloodsubEvent(FloodsubEvent),
+    PubsubMessage(PubsubMessage),
     MdnsEvent(MdnsEvent),
+    IdentifyEvent(IdentifyEvent),
+    AutonatEvent(autonat::Event),
+    PingEvent(ping::Event),
+}
+
+// one line of the event log; libp2p events can't be serialized, so only a description is kept
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+enum RecordedEvent {
+    Input(String),
+    PubsubMessage {
+        source: String,
+        topics: Vec<String>,
+        data: Vec<u8>,
+    },
+    Summary {
+        kind: String,
+        description: String,
+    },
+}
+
+struct EventRecorder {
+    file: fs::File,
 }
 
 #[derive(NetworkBehaviour)]
 #[behaviour(out_event = "StoryBehaviourEvent")]
 struct StoryBehaviour {
-    floodsub: Floodsub,
+    floodsub: Toggle<Floodsub>,
+    gossipsub: Toggle<Gossipsub>,
     mdns: Mdns,
+    identify: Identify,
+    autonat: autonat::Behaviour,
+    ping: ping::Behaviour,
 }
 #[derive(Debug)]
 enum StoryBehaviourEvent {
     Floodsub(FloodsubEvent),
+    Gossipsub(GossipsubEvent),
     Mdns(MdnsEvent),
+    Identify(IdentifyEvent),
+    Autonat(autonat::Event),
+    Ping(ping::Event),
 }
 
 impl From<FloodsubEvent> for StoryBehaviourEvent {
@@ -81,12 +287,183 @@ impl From<FloodsubEvent> for StoryBehaviourEvent {
     }
 }
 
+impl From<GossipsubEvent> for StoryBehaviourEvent {
+    fn from(event: GossipsubEvent) -> Self {
+        StoryBehaviourEvent::Gossipsub(event)
+    }
+}
+
 impl From<MdnsEvent> for StoryBehaviourEvent {
     fn from(event: MdnsEvent) -> Self {
         StoryBehaviourEvent::Mdns(event)
     }
 }
 
+impl From<IdentifyEvent> for StoryBehaviourEvent {
+    fn from(event: IdentifyEvent) -> Self {
+        StoryBehaviourEvent::Identify(event)
+    }
+}
+
+impl From<autonat::Event> for StoryBehaviourEvent {
+    fn from(event: autonat::Event) -> Self {
+        StoryBehaviourEvent::Autonat(event)
+    }
+}
+
+impl From<ping::Event> for StoryBehaviourEvent {
+    fn from(event: ping::Event) -> Self {
+        StoryBehaviourEvent::Ping(event)
+    }
+}
+
+impl From<&EventType> for RecordedEvent {
+    fn from(event: &EventType) -> Self {
+        let summary = |kind: &str, description: String| RecordedEvent::Summary {
+            kind: kind.to_owned(),
+            description,
+        };
+        match event {
+            EventType::Input(line) => RecordedEvent::Input(line.clone()),
+            EventType::PubsubMessage(msg) => RecordedEvent::PubsubMessage {
+                source: msg.source.to_string(),
+                topics: msg.topics.clone(),
+                data: msg.data.clone(),
+            },
+            EventType::Response(resp) => summary(
+                "Response",
+                format!("{} stories for {}", resp.data.len(), resp.receiver),
+            ),
+            EventType::MdnsEvent(e) => summary("Mdns", format!("{:?}", e)),
+            EventType::IdentifyEvent(e) => summary("Identify", format!("{:?}", e)),
+            EventType::AutonatEvent(e) => summary("Autonat", format!("{:?}", e)),
+            EventType::PingEvent(e) => summary("Ping", format!("{:?}", e)),
+        }
+    }
+}
+
+impl EventRecorder {
+    async fn open(path: &str) -> Result<Self> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        Ok(EventRecorder { file })
+    }
+
+    async fn record(&mut self, event: &EventType) -> Result<()> {
+        let mut json = serde_json::to_string(&RecordedEvent::from(event))?;
+        json.push('\n');
+        self.file.write_all(json.as_bytes()).await?;
+        self.file.flush().await?;
+        Ok(())
+    }
+}
+
+impl From<FloodsubMessage> for PubsubMessage {
+    fn from(msg: FloodsubMessage) -> Self {
+        PubsubMessage {
+            source: msg.source,
+            topics: msg.topics.iter().map(|t| t.id().to_owned()).collect(),
+            data: msg.data,
+        }
+    }
+}
+
+impl PubsubMessage {
+    fn from_gossipsub(msg: GossipsubMessage, propagation_source: PeerId) -> Self {
+        PubsubMessage {
+            source: msg.source.unwrap_or(propagation_source),
+            topics: vec![msg.topic.into_string()],
+            data: msg.data,
+        }
+    }
+}
+
+impl StoryBehaviour {
+    async fn new(keys: &identity::Keypair, pubsub: PubsubKind) -> Self {
+        let floodsub = match pubsub {
+            PubsubKind::Floodsub => Some(Floodsub::new(PeerId::from(keys.public()))),
+            PubsubKind::Gossipsub => None,
+        };
+        let gossipsub = match pubsub {
+            PubsubKind::Floodsub => None,
+            PubsubKind::Gossipsub => Some(
+                Gossipsub::new(
+                    MessageAuthenticity::Signed(keys.clone()),
+                    GossipsubConfig::default(),
+                )
+                .expect("can create gossipsub"),
+            ),
+        };
+        StoryBehaviour {
+            floodsub: floodsub.into(),
+            gossipsub: gossipsub.into(),
+            mdns: Mdns::new(Default::default())
+                .await
+                .expect("can create mdns"),
+            identify: Identify::new(IdentifyConfig::new(
+                PROTOCOL_VERSION.to_owned(),
+                keys.public(),
+            )),
+            autonat: autonat::Behaviour::new(PeerId::from(keys.public()), Default::default()),
+            ping: ping::Behaviour::new(ping::Config::new().with_keep_alive(true)),
+        }
+    }
+
+    fn publish(&mut self, topic: Topic, data: &[u8]) {
+        if let Some(floodsub) = self.floodsub.as_mut() {
+            floodsub.publish(topic, data);
+        } else if let Some(gossipsub) = self.gossipsub.as_mut() {
+            match gossipsub.publish(IdentTopic::new(topic.id()), data) {
+                Ok(_) => {}
+                // floodsub drops these silently, only note it
+                Err(PublishError::InsufficientPeers) => {
+                    info!("no peers to publish to on topic {}", topic.id())
+                }
+                Err(e) => error!("error publishing to topic {}: {:?}", topic.id(), e),
+            }
+        }
+    }
+
+    fn subscribe(&mut self, topic: Topic) {
+        if let Some(floodsub) = self.floodsub.as_mut() {
+            floodsub.subscribe(topic);
+        } else if let Some(gossipsub) = self.gossipsub.as_mut() {
+            if let Err(e) = gossipsub.subscribe(&IdentTopic::new(topic.id())) {
+                error!("error subscribing to topic {}: {:?}", topic.id(), e);
+            }
+        }
+    }
+
+    fn unsubscribe(&mut self, topic: Topic) {
+        if let Some(floodsub) = self.floodsub.as_mut() {
+            floodsub.unsubscribe(topic);
+        } else if let Some(gossipsub) = self.gossipsub.as_mut() {
+            if let Err(e) = gossipsub.unsubscribe(&IdentTopic::new(topic.id())) {
+                error!("error unsubscribing from topic {}: {:?}", topic.id(), e);
+            }
+        }
+    }
+
+    fn add_peer(&mut self, peer: PeerId) {
+        if let Some(floodsub) = self.floodsub.as_mut() {
+            floodsub.add_node_to_partial_view(peer);
+        } else if let Some(gossipsub) = self.gossipsub.as_mut() {
+            gossipsub.add_explicit_peer(&peer);
+        }
+    }
+
+    fn remove_peer(&mut self, peer: &PeerId) {
+        if let Some(floodsub) = self.floodsub.as_mut() {
+            floodsub.remove_node_from_partial_view(peer);
+        } else if let Some(gossipsub) = self.gossipsub.as_mut() {
+            gossipsub.remove_explicit_peer(peer);
+        }
+    }
+}
+
 fn respond_with_public_stories(sender: mpsc::UnboundedSender<ListResponse>, receiver: String) {
     tokio::spawn(async move {
         match read_local_stories().await {
@@ -94,7 +471,7 @@ fn respond_with_public_stories(sender: mpsc::UnboundedSender<ListResponse>, rece
                 let resp = ListResponse {
                     mode: ListMode::ALL,
                     receiver,
-                    data: stories.into_iter().filter(|r| r.public).collect(),
+                    data: public_stories(stories),
                 };
                 if let Err(e) = sender.send(resp) {
                     error!("error sending response via channel, {}", e);
@@ -105,8 +482,107 @@ fn respond_with_public_stories(sender: mpsc::UnboundedSender<ListResponse>, rece
     });
 }
 
-async fn create_new_story(name: &str, header: &str, body: &str) -> Result<()> {
+fn respond_with_stories_by_title(
+    sender: mpsc::UnboundedSender<ListResponse>,
+    receiver: String,
+    title: String,
+) {
+    tokio::spawn(async move {
+        match read_local_stories().await {
+            Ok(stories) => {
+                let wanted = title.trim().to_lowercase();
+                let data: Stories = public_stories(stories)
+                    .into_iter()
+                    .filter(|r| r.name.trim().to_lowercase() == wanted)
+                    .collect();
+                if data.is_empty() {
+                    return;
+                }
+                let resp = ListResponse {
+                    mode: ListMode::Title(title),
+                    receiver,
+                    data,
+                };
+                if let Err(e) = sender.send(resp) {
+                    error!("error sending response via channel, {}", e);
+                }
+            }
+            Err(e) => error!(
+                "error fetching local stories to answer title request, {}",
+                e
+            ),
+        }
+    });
+}
+
+// favorites are a local preference, so they are cleared before stories leave this node
+fn public_stories(stories: Stories) -> Stories {
+    let titles: HashMap<usize, String> = stories
+        .iter()
+        .filter(|r| r.public)
+        .map(|r| (r.id, r.name.clone()))
+        .collect();
+    stories
+        .into_iter()
+        .filter(|r| r.public)
+        .map(|mut r| {
+            r.favorite = false;
+            r.reply_to_title = r.reply_to.and_then(|id| titles.get(&id).cloned());
+            r
+        })
+        .collect()
+}
+
+fn split_response(resp: ListResponse) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = ListResponse {
+        mode: resp.mode.clone(),
+        receiver: resp.receiver.clone(),
+        data: Vec::new(),
+    };
+    let mut current_json = serde_json::to_string(&current).expect("can jsonify response");
+    for story in resp.data {
+        current.data.push(story);
+        let json = serde_json::to_string(&current).expect("can jsonify response");
+        if json.len() <= MAX_BROADCAST_SIZE {
+            current_json = json;
+            continue;
+        }
+        let story = current.data.pop().expect("story was just pushed");
+        if !current.data.is_empty() {
+            chunks.push(current_json);
+            current.data.clear();
+        }
+        current.data.push(story);
+        current_json = serde_json::to_string(&current).expect("can jsonify response");
+        if current_json.len() > MAX_BROADCAST_SIZE {
+            let story = current.data.pop().expect("story was just pushed");
+            error!(
+                "story with id {} is too large to broadcast ({} bytes max)",
+                story.id, MAX_BROADCAST_SIZE
+            );
+            current_json = serde_json::to_string(&current).expect("can jsonify response");
+        }
+    }
+    if !current.data.is_empty() || chunks.is_empty() {
+        chunks.push(current_json);
+    }
+    chunks
+}
+
+async fn create_new_story(
+    name: &str,
+    header: &str,
+    body: &str,
+    reply_to: Option<usize>,
+    public: bool,
+) -> Result<()> {
     let mut local_stories = read_local_stories().await?;
+    if let Some(id) = reply_to {
+        if !local_stories.iter().any(|r| r.id == id) {
+            return Err(format!("no story with id {} to reply to", id).into());
+        }
+    }
     let new_id = match local_stories.iter().max_by_key(|r| r.id) {
         Some(v) => v.id + 1,
         None => 0,
@@ -116,7 +592,10 @@ async fn create_new_story(name: &str, header: &str, body: &str) -> Result<()> {
         name: name.to_owned(),
         header: header.to_owned(),
         body: body.to_owned(),
-        public: false,
+        public,
+        favorite: false,
+        reply_to,
+        reply_to_title: None,
     });
     write_local_stories(&local_stories).await?;
 
@@ -124,6 +603,7 @@ async fn create_new_story(name: &str, header: &str, body: &str) -> Result<()> {
     info!("Name: {}", name);
     info!("Header: {}", header);
     info!("Body:: {}", body);
+    info!("Public: {}", public);
 
     Ok(())
 }
@@ -138,15 +618,116 @@ async fn publish_story(id: usize) -> Result<()> {
     Ok(())
 }
 
+async fn set_story_favorite(id: usize, favorite: bool) -> Result<()> {
+    let mut local_stories = read_local_stories().await?;
+    match local_stories.iter_mut().find(|r| r.id == id) {
+        Some(story) => story.favorite = favorite,
+        None => return Err(format!("no story with id {}", id).into()),
+    }
+    write_local_stories(&local_stories).await?;
+    Ok(())
+}
+
 async fn read_local_stories() -> Result<Stories> {
-    let content = fs::read(STORAGE_FILE_PATH).await?;
-    let result = serde_json::from_slice(&content)?;
-    Ok(result)
+    match fs::read(&*STORAGE_PATH).await {
+        Ok(content) => Ok(serde_json::from_slice(&content)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// every story in display order, and the range of it that makes up the requested page
+async fn read_local_stories_paged(
+    offset: usize,
+    limit: usize,
+    sort: SortMode,
+) -> Result<(Stories, Range<usize>)> {
+    let local_stories = read_local_stories().await?;
+    Ok(page_stories(local_stories, offset, limit, sort))
+}
+
+fn page_stories(
+    mut local_stories: Stories,
+    offset: usize,
+    limit: usize,
+    sort: SortMode,
+) -> (Stories, Range<usize>) {
+    match sort {
+        SortMode::Oldest => local_stories.sort_by_key(|r| r.id),
+        SortMode::Newest => local_stories.sort_by_key(|r| std::cmp::Reverse(r.id)),
+        SortMode::Title => local_stories.sort_by_key(|r| r.name.trim().to_lowercase()),
+    }
+    local_stories.sort_by_key(|r| !r.favorite);
+    let start = offset.min(local_stories.len());
+    let end = start.saturating_add(limit).min(local_stories.len());
+    (local_stories, start..end)
+}
+
+async fn read_subscribed_topics() -> Result<Vec<String>> {
+    match fs::read(&*TOPICS_PATH).await {
+        Ok(content) => Ok(serde_json::from_slice(&content)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn write_subscribed_topics(topics: &[String]) -> Result<()> {
+    let json = serde_json::to_string(topics)?;
+    write_data_file(&TOPICS_PATH, &json).await
 }
 
 async fn write_local_stories(stories: &Stories) -> Result<()> {
     let json = serde_json::to_string(&stories)?;
-    fs::write(STORAGE_FILE_PATH, &json).await?;
+    write_data_file(&STORAGE_PATH, &json).await
+}
+
+async fn read_aliases() -> Result<HashMap<String, String>> {
+    match fs::read(&*ALIASES_PATH).await {
+        Ok(content) => Ok(serde_json::from_slice(&content)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn save_alias(name: &str, command: &str) -> Result<()> {
+    let mut aliases: HashMap<String, String> = match fs::read(&*ALIASES_PATH).await {
+        Ok(content) => serde_json::from_slice(&content)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+        Err(e) => return Err(e.into()),
+    };
+    aliases.insert(name.to_owned(), command.to_owned());
+    let json = serde_json::to_string(&aliases)?;
+    write_data_file(&ALIASES_PATH, &json).await
+}
+
+async fn read_nicknames() -> Result<HashMap<String, String>> {
+    match fs::read(&*NICKNAMES_PATH).await {
+        Ok(content) => Ok(serde_json::from_slice(&content)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn write_nicknames(nicknames: &HashMap<String, String>) -> Result<()> {
+    let json = serde_json::to_string(nicknames)?;
+    write_data_file(&NICKNAMES_PATH, &json).await
+}
+
+fn display_name(peer: &PeerId) -> String {
+    let peer = peer.to_string();
+    match NICKNAMES.lock().expect("can lock nicknames").get(&peer) {
+        Some(nick) => format!("{} ({})", nick, peer),
+        None => peer,
+    }
+}
+
+async fn write_data_file(path: &str, contents: &str) -> Result<()> {
+    if let Some(parent) = Path::new(path).parent() {
+        if !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent).await?;
+        }
+    }
+    fs::write(path, contents).await?;
     Ok(())
 }
 
@@ -155,8 +736,15 @@ async fn main() {
     pretty_env_logger::init();
 
     info!("Peer Id: {}", PEER_ID.clone());
-    let (response_sender, mut response_rcv) = mpsc::unbounded_channel();
-
+    info!("Stories file: {}", *STORAGE_PATH);
+    match read_nicknames().await {
+        Ok(nicknames) => *NICKNAMES.lock().expect("can lock nicknames") = nicknames,
+        Err(e) => error!("error reading nicknames: {}", e),
+    }
+    match read_aliases().await {
+        Ok(aliases) => ALIASES.lock().expect("can lock aliases").extend(aliases),
+        Err(e) => error!("error reading aliases: {}", e),
+    }
     let auth_keys = Keypair::<X25519Spec>::new()
         .into_authentic(&KEYS)
         .expect("can create auth keys");
@@ -166,15 +754,16 @@ async fn main() {
         .authenticate(NoiseConfig::xx(auth_keys).into_authenticated()) // XX Handshake pattern, IX exists as well and IK - only XX currently provides interop with other libp2p impls
         .multiplex(mplex::MplexConfig::new())
         .boxed();
-    let _ping = crate::ping::Ping::new(PingConfig::new().with_keep_alive(true));
-    let mut behaviour = StoryBehaviour {
-        floodsub: Floodsub::new(*PEER_ID),
-        mdns: Mdns::new(Default::default())
-            .await
-            .expect("can create mdns"),
-    };
+    info!("Pubsub: {:?}", *PUBSUB_KIND);
+    let mut behaviour = StoryBehaviour::new(&KEYS, *PUBSUB_KIND).await;
 
-    behaviour.floodsub.subscribe(TOPIC.clone());
+    behaviour.subscribe(TOPIC.clone());
+    match read_subscribed_topics().await {
+        Ok(topics) => topics.into_iter().for_each(|t| {
+            behaviour.subscribe(Topic::new(t));
+        }),
+        Err(e) => error!("error reading subscribed topics: {}", e),
+    }
 
     let mut swarm = SwarmBuilder::new(transp, behaviour, *PEER_ID)
         .executor(Box::new(|fut| {
@@ -182,7 +771,19 @@ async fn main() {
         }))
         .build();
 
-    let mut stdin = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+    let recorder = match &*RECORD_EVENTS_PATH {
+        Some(path) => match EventRecorder::open(path).await {
+            Ok(recorder) => {
+                info!("Recording events to {}", path);
+                Some(recorder)
+            }
+            Err(e) => {
+                error!("error opening event log {}: {}", path, e);
+                None
+            }
+        },
+        None => None,
+    };
 
     Swarm::listen_on(
         &mut swarm,
@@ -192,15 +793,69 @@ async fn main() {
     )
     .expect("swarm can be started");
 
+    let stdin = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+    run_event_loop(&mut swarm, stdin, script_path_from_args(), recorder).await;
+
+    info!("Shutting down");
+}
+
+async fn run_event_loop<R: AsyncBufRead + Unpin>(
+    swarm: &mut Swarm<StoryBehaviour>,
+    mut stdin: Lines<R>,
+    script: Option<String>,
+    mut recorder: Option<EventRecorder>,
+) {
+    let (response_sender, mut response_rcv) = mpsc::unbounded_channel();
+    let (script_sender, mut script_rcv) = mpsc::unbounded_channel();
+    let peer_found = Arc::new(Notify::new());
+    let mut stdin_open = true;
+    let mut script_open = true;
+    if let Some(path) = script {
+        spawn_script(path, peer_found.clone(), script_sender);
+    } else {
+        drop(script_sender);
+    }
+
     loop {
         let evt = {
             tokio::select! {
-                line = stdin.next_line() => Some(EventType::Input(line.expect("can get line").expect("can read line from stdin"))),
+                line = stdin.next_line(), if stdin_open => match line.expect("can get line") {
+                    Some(line) => Some(EventType::Input(line)),
+                    None => {
+                        // a closed stdin (e.g. /dev/null) must not cut a script short
+                        stdin_open = false;
+                        None
+                    }
+                },
+                line = script_rcv.recv(), if script_open => match line {
+                    Some(line) => {
+                        info!("> {}", line);
+                        Some(EventType::Input(line))
+                    }
+                    None => {
+                        script_open = false;
+                        None
+                    }
+                },
                 response = response_rcv.recv() => Some(EventType::Response(response.expect("response exists"))),
                 event = swarm.select_next_some() => {
                     match event {
-                        SwarmEvent::Behaviour(StoryBehaviourEvent::Floodsub(event)) => Some(EventType::FloodsubEvent(event)),
+                        SwarmEvent::Behaviour(StoryBehaviourEvent::Floodsub(FloodsubEvent::Message(msg))) => Some(EventType::PubsubMessage(msg.into())),
+                        SwarmEvent::Behaviour(StoryBehaviourEvent::Gossipsub(GossipsubEvent::Message { propagation_source, message, .. })) => Some(EventType::PubsubMessage(PubsubMessage::from_gossipsub(message, propagation_source))),
+                        SwarmEvent::Behaviour(StoryBehaviourEvent::Floodsub(_) | StoryBehaviourEvent::Gossipsub(_)) => {
+                            info!("Subscription events");
+                            None
+                        }
                         SwarmEvent::Behaviour(StoryBehaviourEvent::Mdns(event)) => Some(EventType::MdnsEvent(event)),
+                        SwarmEvent::Behaviour(StoryBehaviourEvent::Identify(event)) => Some(EventType::IdentifyEvent(event)),
+                        SwarmEvent::Behaviour(StoryBehaviourEvent::Autonat(event)) => Some(EventType::AutonatEvent(event)),
+                        SwarmEvent::Behaviour(StoryBehaviourEvent::Ping(event)) => Some(EventType::PingEvent(event)),
+                        SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                            // a ping requested before the disconnect must not report a later connection
+                            take_pending_ping(&peer_id);
+                            info!("Disconnected from {}", peer_id);
+                            None
+                        }
                         _ => {
                             info!("Unhandled Swarm Event: {:?}", event);
                             None
@@ -210,153 +865,1452 @@ async fn main() {
             }
         };
 
+        if !stdin_open && !script_open {
+            info!("No more input");
+            break;
+        }
+
         if let Some(event) = evt {
+            if let Some(recorder) = recorder.as_mut() {
+                if let Err(e) = recorder.record(&event).await {
+                    error!("error recording event: {}", e);
+                }
+            }
             match event {
-                EventType::Response(resp) => {
-                    let json = serde_json::to_string(&resp).expect("can jsonify response");
-                    swarm
-                        .behaviour_mut()
-                        .floodsub
-                        .publish(TOPIC.clone(), json.as_bytes());
+                EventType::Response(resp) => publish_response(swarm, resp),
+                EventType::Input(line) => {
+                    if !handle_input_event(&line, swarm).await {
+                        break;
+                    }
                 }
-                EventType::Input(line) => match line.as_str() {
-                    "ls p" => handle_list_peers(&mut swarm).await,
-                    cmd if cmd.starts_with("ls s") => handle_list_stories(cmd, &mut swarm).await,
-                    cmd if cmd.starts_with("create s") => handle_create_stories(cmd).await,
-                    cmd if cmd.starts_with("publish s") => handle_publish_story(cmd).await,
-                    _ => error!("unknown command"),
-                },
                 EventType::MdnsEvent(mdns_event) => match mdns_event {
                     MdnsEvent::Discovered(discovered_list) => {
-                        for (peer, _addr) in discovered_list {
-                            info!("Disocvered a peer:{} at {}", peer, _addr);
-                            swarm
-                                .behaviour_mut()
-                                .floodsub
-                                .add_node_to_partial_view(peer);
+                        peer_found.notify_one();
+                        // addresses come off the network, so they could carry escape sequences
+                        for (peer, addr) in discovered_list {
+                            info!(
+                                "Disocvered a peer:{} at {}",
+                                peer,
+                                sanitize_for_display(&addr.to_string())
+                            );
+                            swarm.behaviour_mut().add_peer(peer);
                         }
                     }
                     MdnsEvent::Expired(expired_list) => {
-                        for (peer, _addr) in expired_list {
-                            info!("Expired a peer:{} at {}", peer, _addr);
+                        for (peer, addr) in expired_list {
+                            info!(
+                                "Expired a peer:{} at {}",
+                                peer,
+                                sanitize_for_display(&addr.to_string())
+                            );
                             if !swarm.behaviour_mut().mdns.has_node(&peer) {
-                                swarm
-                                    .behaviour_mut()
-                                    .floodsub
-                                    .remove_node_from_partial_view(&peer);
+                                swarm.behaviour_mut().remove_peer(&peer);
                             }
                         }
                     }
                 },
-                EventType::FloodsubEvent(floodsub_event) => match floodsub_event {
-                    FloodsubEvent::Message(msg) => {
-                        if let Ok(resp) = serde_json::from_slice::<ListResponse>(&msg.data) {
-                            if resp.receiver == PEER_ID.to_string() {
-                                info!("Response from {}:", msg.source);
-                                resp.data.iter().for_each(|r| info!("{:?}", r));
-                            }
-                        } else if let Ok(req) = serde_json::from_slice::<ListRequest>(&msg.data) {
-                            match req.mode {
-                                ListMode::ALL => {
-                                    info!("Received ALL req: {:?} from {:?}", req, msg.source);
-                                    respond_with_public_stories(
-                                        response_sender.clone(),
-                                        msg.source.to_string(),
-                                    );
-                                }
-                                ListMode::One(ref peer_id) => {
-                                    if peer_id == &PEER_ID.to_string() {
-                                        info!("Received req: {:?} from {:?}", req, msg.source);
-                                        respond_with_public_stories(
-                                            response_sender.clone(),
-                                            msg.source.to_string(),
-                                        );
-                                    }
-                                }
-                            }
+                EventType::IdentifyEvent(identify_event) => {
+                    if let IdentifyEvent::Received {
+                        peer_id,
+                        info: peer_info,
+                    } = identify_event
+                    {
+                        info!(
+                            "Peer {} observed us at {}",
+                            peer_id,
+                            sanitize_for_display(&peer_info.observed_addr.to_string())
+                        );
+                        if peer_info.public_key.to_peer_id() == peer_id {
+                            PEER_KEYS
+                                .lock()
+                                .expect("can lock peer keys")
+                                .insert(peer_id, peer_info.public_key);
                         }
                     }
-                    _ => {
-                        info!("Subscription events");
+                }
+                EventType::AutonatEvent(autonat_event) => {
+                    if let autonat::Event::StatusChanged { old, new } = autonat_event {
+                        info!("Reachability changed from {:?} to {:?}", old, new);
+                    }
+                }
+                EventType::PingEvent(ping::Event { peer, result }) => match result {
+                    Ok(ping::Success::Ping { rtt }) if take_pending_ping(&peer) => {
+                        info!("Round trip to {}: {:?}", peer, rtt)
+                    }
+                    Err(e) if take_pending_ping(&peer) => {
+                        error!("ping to {} failed: {}", peer, e)
                     }
+                    _ => {}
                 },
+                EventType::PubsubMessage(msg) => handle_pubsub_message(msg, &response_sender).await,
             }
         }
     }
+
+    // publishes queued by earlier commands only go out while the swarm is polled
+    if swarm.connected_peers().next().is_some() {
+        info!("Sending queued messages");
+        let _ = tokio::time::timeout(SHUTDOWN_FLUSH_TIMEOUT, async {
+            loop {
+                tokio::select! {
+                    Some(resp) = response_rcv.recv() => publish_response(swarm, resp),
+                    _ = swarm.select_next_some() => {}
+                }
+            }
+        })
+        .await;
+    }
 }
 
-async fn handle_list_peers(swarm: &mut Swarm<StoryBehaviour>) {
-    info!("Discovered Peers:");
-    let nodes = swarm.behaviour().mdns.discovered_nodes();
-    let mut unique_peers = HashSet::new();
-    for peer in nodes {
-        unique_peers.insert(peer);
+fn publish_response(swarm: &mut Swarm<StoryBehaviour>, resp: ListResponse) {
+    for json in split_response(resp) {
+        swarm
+            .behaviour_mut()
+            .publish(TOPIC.clone(), json.as_bytes());
     }
-    unique_peers.iter().for_each(|p| info!("{}", p));
 }
 
-async fn handle_list_stories(cmd: &str, swarm: &mut Swarm<StoryBehaviour>) {
-    let rest = cmd.strip_prefix("ls s ");
-    match rest {
-        Some("all") => {
-            let req = ListRequest {
-                mode: ListMode::ALL,
-            };
-            let json = serde_json::to_string(&req).expect("can jsonify request");
-            swarm
-                .behaviour_mut()
-                .floodsub
-                .publish(TOPIC.clone(), json.as_bytes());
-        }
-        Some(story_peer_id) => {
-            let req = ListRequest {
-                mode: ListMode::One(story_peer_id.to_owned()),
-            };
-            let json = serde_json::to_string(&req).expect("can jsonify request");
-            swarm
-                .behaviour_mut()
-                .floodsub
-                .publish(TOPIC.clone(), json.as_bytes());
+async fn handle_pubsub_message(
+    msg: PubsubMessage,
+    response_sender: &mpsc::UnboundedSender<ListResponse>,
+) {
+    if !msg.topics.iter().any(|t| t == TOPIC.id()) {
+        let text = sanitize_for_display(&String::from_utf8_lossy(&msg.data));
+        let sender = display_name(&msg.source);
+        msg.topics
+            .iter()
+            .for_each(|t| info!("[{}] {}: {}", sanitize_for_display(t), sender, text));
+        return;
+    }
+    if let Ok(resp) = serde_json::from_slice::<ListResponse>(&msg.data) {
+        if resp.receiver == PEER_ID.to_string() {
+            let sender = display_name(&msg.source);
+            info!("Response from {}:", sender);
+            resp.data.iter().for_each(|r| {
+                info!("{:?}", r);
+                log_reply_to(r, &resp.data);
+            });
         }
-        None => {
-            match read_local_stories().await {
-                Ok(v) => {
-                    info!("Local stories ({})", v.len());
-                    v.iter().for_each(|r| info!("{:?}", r));
+    } else if let Ok(req) = serde_json::from_slice::<ListRequest>(&msg.data) {
+        match req.mode {
+            ListMode::ALL => {
+                info!("Received ALL req: {:?} from {:?}", req, msg.source);
+                respond_with_public_stories(response_sender.clone(), msg.source.to_string());
+            }
+            ListMode::One(ref peer_id) => {
+                if peer_id == &PEER_ID.to_string() {
+                    info!("Received req: {:?} from {:?}", req, msg.source);
+                    respond_with_public_stories(response_sender.clone(), msg.source.to_string());
                 }
-                Err(e) => error!("error fetching local stories: {}", e),
-            };
+            }
+            ListMode::Title(ref title) => {
+                info!("Received title req: {:?} from {:?}", req, msg.source);
+                respond_with_stories_by_title(
+                    response_sender.clone(),
+                    msg.source.to_string(),
+                    title.clone(),
+                );
+            }
         }
-    };
+    }
 }
 
-async fn handle_create_stories(cmd: &str) {
-    if let Some(rest) = cmd.strip_prefix("create s") {
-        let elements: Vec<&str> = rest.split('|').collect();
-        if elements.len() < 3 {
-            info!("too few arguments - Format: name|header|body");
-        } else {
-            let name = elements.get(0).expect("name is there");
-            let header = elements.get(1).expect("header is there");
-            let body = elements.get(2).expect("body is there");
-            if let Err(e) = create_new_story(name, header, body).await {
-                error!("error creating story: {}", e);
-            };
+fn script_path_from_args() -> Option<String> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--script" {
+            let path = args.next();
+            if path.is_none() {
+                error!("--script requires a path");
+            }
+            return path;
         }
     }
+    None
 }
 
-async fn handle_publish_story(cmd: &str) {
-    if let Some(rest) = cmd.strip_prefix("publish s") {
-        match rest.trim().parse::<usize>() {
-            Ok(id) => {
-                if let Err(e) = publish_story(id).await {
-                    info!("error publishing story with id {}, {}", id, e)
-                } else {
-                    info!("Published story with id: {}", id);
-                }
+fn spawn_script(path: String, peer_found: Arc<Notify>, sender: mpsc::UnboundedSender<String>) {
+    tokio::spawn(async move {
+        let content = match fs::read_to_string(&path).await {
+            Ok(content) => content,
+            Err(e) => {
+                error!("error running script {}: {}", path, e);
+                return;
             }
-            Err(e) => error!("invalid id: {}, {}", rest.trim(), e),
         };
+        // give mDNS a chance to find peers so network commands reach someone
+        if tokio::time::timeout(SCRIPT_PEER_WAIT, peer_found.notified())
+            .await
+            .is_err()
+        {
+            info!("No peers found yet, running script {} anyway", path);
+        }
+        for line in script_lines(&content) {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+fn script_lines(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect()
+}
+
+// returns false once the line asks to quit
+async fn handle_input_event(line: &str, swarm: &mut Swarm<StoryBehaviour>) -> bool {
+    let line = expand_alias(line);
+    if matches!(parse_command(&line), Some((Command::Quit, _))) {
+        return false;
+    }
+    handle_input(&line, swarm).await;
+    true
+}
+
+fn expand_alias(line: &str) -> String {
+    let aliases = ALIASES.lock().expect("can lock aliases");
+    let mut expanded = line.to_owned();
+    let mut seen = HashSet::new();
+    loop {
+        let (name, args) = expanded
+            .trim()
+            .split_once(' ')
+            .unwrap_or((expanded.trim(), ""));
+        if is_builtin(name) {
+            return expanded;
+        }
+        match aliases.get(name) {
+            // each alias expands at most once, so alias-to-alias cycles stop
+            Some(command) if seen.insert(name.to_owned()) => {
+                expanded = format!("{} {}", command, args).trim().to_owned();
+            }
+            _ => return expanded,
+        }
+    }
+}
+
+async fn handle_input(line: &str, swarm: &mut Swarm<StoryBehaviour>) {
+    let (command, args) = match parse_command(line) {
+        Some(parsed) => parsed,
+        None => {
+            error!("unknown command");
+            return;
+        }
+    };
+    match command {
+        Command::ListPeers => handle_list_peers(swarm).await,
+        Command::ListStories => handle_list_stories(line, swarm).await,
+        Command::ListTopics => handle_list_topics().await,
+        Command::Reachability => handle_reachability(swarm),
+        Command::Diagnose => handle_diagnose(swarm).await,
+        Command::DbCheck => handle_db_check().await,
+        Command::Fingerprint => handle_fingerprint(line).await,
+        Command::Ping => handle_ping(line, swarm),
+        Command::RequestStory => handle_request_story(line, swarm),
+        Command::CreateStory => handle_create_stories(line).await,
+        Command::PublishStory => handle_publish_story(line).await,
+        Command::Favorite => handle_favorite_story(line, true).await,
+        Command::Unfavorite => handle_favorite_story(line, false).await,
+        Command::Subscribe => handle_subscribe_topic(line, swarm).await,
+        Command::Unsubscribe => handle_unsubscribe_topic(line, swarm).await,
+        Command::PublishToTopic => handle_publish_to_topic(line, swarm),
+        Command::Nick if args.trim().is_empty() => handle_list_nicknames(),
+        Command::Nick => handle_nickname(line).await,
+        Command::Alias if args.trim().is_empty() => handle_list_aliases(),
+        Command::Alias => handle_define_alias(line).await,
+        // handled by handle_input_event before dispatch
+        Command::Quit => {}
+    }
+}
+
+fn parse_command(line: &str) -> Option<(Command, &str)> {
+    COMMANDS
+        .iter()
+        .find_map(|(name, command)| Some((*command, command_args(line, name)?)))
+}
+
+fn is_builtin(word: &str) -> bool {
+    COMMANDS
+        .iter()
+        .any(|(name, _)| name.split(' ').next() == Some(word))
+}
+
+// the arguments after a command word, or None if the line is a different command
+fn command_args<'a>(line: &'a str, command: &str) -> Option<&'a str> {
+    let rest = line.strip_prefix(command)?;
+    (rest.is_empty() || rest.starts_with(char::is_whitespace)).then_some(rest)
+}
+
+async fn handle_list_peers(swarm: &mut Swarm<StoryBehaviour>) {
+    info!("Discovered Peers:");
+    let nodes = swarm.behaviour().mdns.discovered_nodes();
+    let mut unique_peers = HashSet::new();
+    for peer in nodes {
+        unique_peers.insert(peer);
+    }
+    for peer in unique_peers {
+        info!("{}", display_name(peer));
+    }
+}
+
+fn handle_ping(cmd: &str, swarm: &Swarm<StoryBehaviour>) {
+    let name = command_args(cmd, "ping").unwrap_or_default().trim();
+    if name.is_empty() {
+        info!("missing peer - Format: ping <peer_id|nickname>");
+        return;
+    }
+    match resolve_peer(name) {
+        Ok(peer) if swarm.is_connected(&peer) => {
+            PENDING_PINGS
+                .lock()
+                .expect("can lock pending pings")
+                .insert(peer);
+            info!(
+                "Waiting for the next ping round trip to {}",
+                display_name(&peer)
+            );
+        }
+        Ok(peer) => error!("not connected to {}", display_name(&peer)),
+        Err(e) => error!("{} - Format: ping <peer_id|nickname>", e),
+    }
+}
+
+fn take_pending_ping(peer: &PeerId) -> bool {
+    PENDING_PINGS
+        .lock()
+        .expect("can lock pending pings")
+        .remove(peer)
+}
+
+async fn handle_fingerprint(cmd: &str) {
+    let name = command_args(cmd, "fingerprint").unwrap_or_default().trim();
+    if name.is_empty() {
+        info!("Your fingerprint: {}", fingerprint(&KEYS.public()));
+        return;
+    }
+    let peer = match resolve_peer(name) {
+        Ok(peer) => peer,
+        Err(e) => {
+            error!("{} - Format: fingerprint [peer_id|nickname]", e);
+            return;
+        }
+    };
+    match PEER_KEYS.lock().expect("can lock peer keys").get(&peer) {
+        Some(key) => info!("Fingerprint of {}: {}", peer, fingerprint(key)),
+        None => error!("no key known for {} yet, connect to it first", peer),
+    }
+}
+
+// accepts a peer id or a nickname set with `nick`
+fn resolve_peer(name: &str) -> Result<PeerId> {
+    if let Ok(peer) = name.parse::<PeerId>() {
+        return Ok(peer);
+    }
+    let nicknames = NICKNAMES.lock().expect("can lock nicknames");
+    match nicknames.iter().find(|(_, nick)| nick.as_str() == name) {
+        Some((peer, _)) => Ok(peer.parse::<PeerId>()?),
+        None => Err(format!("no peer id or nickname {}", name).into()),
+    }
+}
+
+// the first 16 bytes of the SHA-256 of the protobuf-encoded key, as eight groups of
+// four hex digits, short enough to read out over another channel
+fn fingerprint(key: &identity::PublicKey) -> String {
+    let digest = Sha256::digest(key.to_protobuf_encoding());
+    let hex: Vec<String> = digest[..16].iter().map(|b| format!("{:02x}", b)).collect();
+    hex.chunks(2)
+        .map(|group| group.concat())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn handle_reachability(swarm: &Swarm<StoryBehaviour>) {
+    match swarm.behaviour().autonat.nat_status() {
+        autonat::NatStatus::Public(addr) => info!("Publicly reachable at {}", addr),
+        autonat::NatStatus::Private => info!("Behind NAT - peers could not dial us back"),
+        autonat::NatStatus::Unknown => {
+            info!("Reachability unknown - no peer has probed us yet")
+        }
+    }
+    // what peers saw us as, not proof that anyone can dial in
+    let observed: Vec<&Multiaddr> = swarm.external_addresses().map(|r| &r.addr).collect();
+    if !observed.is_empty() {
+        info!("Observed addresses:");
+        observed.iter().for_each(|a| info!("{}", a));
+    }
+}
+
+async fn handle_diagnose(swarm: &Swarm<StoryBehaviour>) {
+    info!("Diagnostics:");
+    report_check("stories file readable", read_local_stories().await.is_ok());
+    report_check("stories file writable", storage_writable().await);
+    report_check(
+        "topics file readable",
+        read_subscribed_topics().await.is_ok(),
+    );
+    report_check(
+        "listening on at least one address",
+        swarm.listeners().next().is_some(),
+    );
+    report_check(
+        "mDNS has discovered peers",
+        swarm.behaviour().mdns.discovered_nodes().next().is_some(),
+    );
+    report_check(
+        "connected to at least one peer",
+        swarm.connected_peers().next().is_some(),
+    );
+}
+
+async fn handle_db_check() {
+    match check_stories_file().await {
+        Ok(DbCheck::Missing) => info!(
+            "stories file {} does not exist yet, nothing to check",
+            *STORAGE_PATH
+        ),
+        Ok(DbCheck::Healthy(count)) => info!("stories file ok ({} stories)", count),
+        Ok(DbCheck::Problems(problems)) => {
+            problems.iter().for_each(|p| error!("{}", p));
+            error!(
+                "stories file {} has {} problem(s), edit it to fix the entries above",
+                *STORAGE_PATH,
+                problems.len()
+            );
+        }
+        Err(e) => {
+            error!("stories file {} is unreadable: {}", *STORAGE_PATH, e);
+            error!("restore it from a backup, or move it aside to start with no stories");
+        }
+    }
+}
+
+async fn check_stories_file() -> Result<DbCheck> {
+    // read_local_stories treats a missing file as empty, which would pass as healthy
+    if let Err(e) = fs::metadata(&*STORAGE_PATH).await {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            return Ok(DbCheck::Missing);
+        }
+    }
+    let stories = read_local_stories().await?;
+    let mut problems = Vec::new();
+    let mut ids = HashSet::new();
+    for story in stories.iter() {
+        if !ids.insert(story.id) {
+            problems.push(format!("duplicate story id {}", story.id));
+        }
+    }
+    for story in stories.iter() {
+        if let Some(parent) = story.reply_to {
+            if !ids.contains(&parent) {
+                problems.push(format!(
+                    "story {} replies to missing story {}",
+                    story.id, parent
+                ));
+            }
+        }
+    }
+    if problems.is_empty() {
+        Ok(DbCheck::Healthy(stories.len()))
+    } else {
+        Ok(DbCheck::Problems(problems))
+    }
+}
+
+fn report_check(name: &str, passed: bool) {
+    if passed {
+        info!("✓ {}", name);
+    } else {
+        error!("✗ {}", name);
+    }
+}
+
+async fn storage_writable() -> bool {
+    let path = Path::new(&*STORAGE_PATH);
+    match fs::OpenOptions::new().append(true).open(path).await {
+        Ok(_) => true,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            // an empty stories file would not parse, so probe the directory instead
+            for dir in path.ancestors().skip(1) {
+                let dir = if dir.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    dir
+                };
+                if fs::metadata(dir).await.is_ok() {
+                    return dir_writable(dir).await;
+                }
+            }
+            false
+        }
+        Err(_) => false,
+    }
+}
+
+async fn dir_writable(dir: &Path) -> bool {
+    let probe = dir.join(format!(".p2p-play-write-test-{}", std::process::id()));
+    match fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+        .await
+    {
+        Ok(_) => fs::remove_file(&probe).await.is_ok(),
+        Err(_) => false,
+    }
+}
+
+async fn handle_list_stories(cmd: &str, swarm: &mut Swarm<StoryBehaviour>) {
+    let rest = command_args(cmd, "ls s")
+        .map(str::trim)
+        .filter(|rest| !rest.is_empty());
+    match rest {
+        Some("all") => {
+            let req = ListRequest {
+                mode: ListMode::ALL,
+            };
+            let json = serde_json::to_string(&req).expect("can jsonify request");
+            swarm
+                .behaviour_mut()
+                .publish(TOPIC.clone(), json.as_bytes());
+        }
+        Some(args) if args.starts_with("--") => handle_list_local_stories(args).await,
+        Some(story_peer_id) => {
+            let req = ListRequest {
+                mode: ListMode::One(story_peer_id.to_owned()),
+            };
+            let json = serde_json::to_string(&req).expect("can jsonify request");
+            swarm
+                .behaviour_mut()
+                .publish(TOPIC.clone(), json.as_bytes());
+        }
+        None => handle_list_local_stories("").await,
+    };
+}
+
+fn handle_request_story(cmd: &str, swarm: &mut Swarm<StoryBehaviour>) {
+    if let Some(rest) = command_args(cmd, "request story") {
+        let title = rest.trim();
+        if title.is_empty() {
+            info!("missing title - Format: request story <title>");
+            return;
+        }
+        let req = ListRequest {
+            mode: ListMode::Title(title.to_owned()),
+        };
+        let json = serde_json::to_string(&req).expect("can jsonify request");
+        if json.len() > MAX_BROADCAST_SIZE {
+            error!(
+                "title too long to broadcast: {} bytes ({} bytes max)",
+                json.len(),
+                MAX_BROADCAST_SIZE
+            );
+            return;
+        }
+        swarm
+            .behaviour_mut()
+            .publish(TOPIC.clone(), json.as_bytes());
+    }
+}
+
+async fn handle_list_local_stories(args: &str) {
+    let opts = match parse_list_args(args) {
+        Ok(v) => v,
+        Err(e) => {
+            error!(
+                "{} - Format: ls s [--page <n>] [--size <n>] [--sort oldest|newest|title]",
+                e
+            );
+            return;
+        }
+    };
+    let offset = (opts.page - 1).saturating_mul(opts.size);
+    match read_local_stories_paged(offset, opts.size, opts.sort).await {
+        Ok((all_stories, range)) => {
+            let total = all_stories.len();
+            let stories = &all_stories[range];
+            info!("Local stories ({})", total);
+            stories.iter().for_each(|r| {
+                let star = if r.favorite { "★ " } else { "" };
+                info!("{}{:?}", star, r);
+                log_reply_to(r, &all_stories);
+            });
+            if stories.is_empty() {
+                info!("showing 0 of {}", total);
+            } else {
+                info!(
+                    "showing {}-{} of {}",
+                    offset + 1,
+                    offset + stories.len(),
+                    total
+                );
+            }
+        }
+        Err(e) => error!("error fetching local stories: {}", e),
+    };
+}
+
+fn log_reply_to(story: &Story, known: &[Story]) {
+    if let Some(id) = story.reply_to {
+        let title = known
+            .iter()
+            .find(|r| r.id == id)
+            .map(|parent| parent.name.as_str())
+            .or(story.reply_to_title.as_deref());
+        match title {
+            Some(title) => info!("  In reply to: {}", sanitize_for_display(title.trim())),
+            None => info!("  In reply to: story {} (not available)", id),
+        }
+    }
+}
+
+// drops control characters, including the ESC that starts ANSI sequences
+fn sanitize_for_display(text: &str) -> String {
+    text.chars().filter(|c| !c.is_control()).collect()
+}
+
+fn parse_list_args(args: &str) -> std::result::Result<ListOptions, String> {
+    let mut opts = ListOptions {
+        page: 1,
+        size: *PAGE_SIZE,
+        sort: SortMode::Oldest,
+    };
+    let mut parts = args.split_whitespace();
+    while let Some(flag) = parts.next() {
+        let value = parts
+            .next()
+            .ok_or_else(|| format!("missing value for {}", flag))?;
+        match flag {
+            "--page" => opts.page = parse_count(flag, value)?,
+            "--size" => opts.size = parse_count(flag, value)?,
+            "--sort" => {
+                opts.sort = match value {
+                    "oldest" => SortMode::Oldest,
+                    "newest" => SortMode::Newest,
+                    "title" => SortMode::Title,
+                    _ => return Err(format!("invalid value for {}: {}", flag, value)),
+                }
+            }
+            _ => return Err(format!("unknown option {}", flag)),
+        }
+    }
+    if opts.page == 0 || opts.size == 0 {
+        return Err("page and size must be at least 1".to_owned());
+    }
+    Ok(opts)
+}
+
+fn parse_count(flag: &str, value: &str) -> std::result::Result<usize, String> {
+    value
+        .parse::<usize>()
+        .map_err(|e| format!("invalid value for {}: {}, {}", flag, value, e))
+}
+
+// an optional --public/--private flag, which must be followed by a space or the first `|`
+fn parse_visibility(args: &str) -> (bool, &str) {
+    let trimmed = args.trim_start();
+    for (flag, public) in [("--public", true), ("--private", false)] {
+        if let Some(rest) = trimmed.strip_prefix(flag) {
+            if rest.is_empty() || rest.starts_with(|c: char| c.is_whitespace() || c == '|') {
+                return (public, rest);
+            }
+        }
+    }
+    (*DEFAULT_PUBLIC, args)
+}
+
+async fn handle_create_stories(cmd: &str) {
+    if let Some(rest) = command_args(cmd, "create s") {
+        let (public, rest) = parse_visibility(rest);
+        let elements: Vec<&str> = rest.split('|').collect();
+        if elements.len() < 3 {
+            info!("too few arguments - Format: name|header|body");
+        } else {
+            let name = elements.first().expect("name is there");
+            let header = elements.get(1).expect("header is there");
+            let body = elements.get(2).expect("body is there");
+            let reply_to = match elements.get(3).map(|r| r.trim()) {
+                None => None,
+                Some(arg) => match arg.strip_prefix("replyto:").map(str::parse::<usize>) {
+                    Some(Ok(id)) => Some(id),
+                    _ => {
+                        info!("invalid reply - Format: name|header|body|replyto:<id>");
+                        return;
+                    }
+                },
+            };
+            if let Err(e) = create_new_story(name, header, body, reply_to, public).await {
+                error!("error creating story: {}", e);
+            };
+        }
+    }
+}
+
+async fn handle_publish_story(cmd: &str) {
+    if let Some(rest) = command_args(cmd, "publish s") {
+        match rest.trim().parse::<usize>() {
+            Ok(id) => {
+                if let Err(e) = publish_story(id).await {
+                    info!("error publishing story with id {}, {}", id, e)
+                } else {
+                    info!("Published story with id: {}", id);
+                }
+            }
+            Err(e) => error!("invalid id: {}, {}", rest.trim(), e),
+        };
+    }
+}
+
+async fn handle_favorite_story(cmd: &str, favorite: bool) {
+    let command = if favorite { "fav s" } else { "unfav s" };
+    if let Some(rest) = command_args(cmd, command) {
+        match rest.trim().parse::<usize>() {
+            Ok(id) => {
+                if let Err(e) = set_story_favorite(id, favorite).await {
+                    error!("error updating favorite for story with id {}, {}", id, e)
+                } else if favorite {
+                    info!("Added story with id {} to favorites", id);
+                } else {
+                    info!("Removed story with id {} from favorites", id);
+                }
+            }
+            Err(e) => error!("invalid id: {}, {}", rest.trim(), e),
+        };
+    }
+}
+
+async fn handle_list_topics() {
+    match read_subscribed_topics().await {
+        Ok(topics) => {
+            info!("Subscribed topics ({})", topics.len() + 1);
+            info!("{}", TOPIC.id());
+            topics.iter().for_each(|t| info!("{}", t));
+        }
+        Err(e) => error!("error fetching subscribed topics: {}", e),
+    }
+}
+
+async fn handle_subscribe_topic(cmd: &str, swarm: &mut Swarm<StoryBehaviour>) {
+    if let Some(rest) = command_args(cmd, "sub t") {
+        let name = rest.trim();
+        if name.is_empty() || name.contains(char::is_whitespace) || name == TOPIC.id() {
+            info!("invalid topic name, names cannot contain spaces - Format: sub t <name>");
+            return;
+        }
+        let mut topics = match read_subscribed_topics().await {
+            Ok(topics) => topics,
+            Err(e) => {
+                error!("error fetching subscribed topics: {}", e);
+                return;
+            }
+        };
+        if topics.iter().any(|t| t == name) {
+            info!("Already subscribed to topic {}", name);
+            return;
+        }
+        topics.push(name.to_owned());
+        if let Err(e) = write_subscribed_topics(&topics).await {
+            error!("error saving subscribed topics: {}", e);
+            return;
+        }
+        swarm.behaviour_mut().subscribe(Topic::new(name));
+        info!("Subscribed to topic {}", name);
+    }
+}
+
+async fn handle_unsubscribe_topic(cmd: &str, swarm: &mut Swarm<StoryBehaviour>) {
+    if let Some(rest) = command_args(cmd, "unsub t") {
+        let name = rest.trim();
+        let mut topics = match read_subscribed_topics().await {
+            Ok(topics) => topics,
+            Err(e) => {
+                error!("error fetching subscribed topics: {}", e);
+                return;
+            }
+        };
+        if !topics.iter().any(|t| t == name) {
+            info!("Not subscribed to topic {}", name);
+            return;
+        }
+        topics.retain(|t| t != name);
+        if let Err(e) = write_subscribed_topics(&topics).await {
+            error!("error saving subscribed topics: {}", e);
+            return;
+        }
+        swarm.behaviour_mut().unsubscribe(Topic::new(name));
+        info!("Unsubscribed from topic {}", name);
+    }
+}
+
+fn handle_publish_to_topic(cmd: &str, swarm: &mut Swarm<StoryBehaviour>) {
+    if let Some(rest) = command_args(cmd, "pub t") {
+        match rest.trim().split_once(' ') {
+            // the topic name travels in the same RPC as the message
+            Some((name, message)) if name.len() + message.len() > MAX_BROADCAST_SIZE => error!(
+                "message too large to broadcast: {} bytes with the topic name ({} bytes max)",
+                name.len() + message.len(),
+                MAX_BROADCAST_SIZE
+            ),
+            Some((name, message)) if name != TOPIC.id() => {
+                swarm
+                    .behaviour_mut()
+                    .publish(Topic::new(name), message.as_bytes());
+            }
+            _ => info!("invalid arguments - Format: pub t <topic> <message>"),
+        }
+    }
+}
+
+fn handle_list_aliases() {
+    let aliases = ALIASES.lock().expect("can lock aliases");
+    info!("Aliases ({})", aliases.len());
+    let mut names: Vec<&String> = aliases.keys().collect();
+    names.sort();
+    names
+        .iter()
+        .for_each(|name| info!("{} -> {}", name, aliases[*name]));
+}
+
+async fn handle_define_alias(cmd: &str) {
+    if let Some(rest) = cmd.strip_prefix("alias ") {
+        match rest.trim().split_once(' ') {
+            Some((name, _)) if is_builtin(name) => {
+                error!("{} is a built-in command and cannot be an alias", name)
+            }
+            Some((name, command)) if !command.trim().is_empty() => {
+                if let Err(e) = save_alias(name, command.trim()).await {
+                    error!("error saving alias {}: {}", name, e);
+                } else {
+                    ALIASES
+                        .lock()
+                        .expect("can lock aliases")
+                        .insert(name.to_owned(), command.trim().to_owned());
+                    info!("Saved alias {} -> {}", name, command.trim());
+                }
+            }
+            _ => info!("invalid arguments - Format: alias <name> <command>"),
+        }
+    }
+}
+
+fn handle_list_nicknames() {
+    let nicknames = NICKNAMES.lock().expect("can lock nicknames");
+    info!("Nicknames ({})", nicknames.len());
+    nicknames
+        .iter()
+        .for_each(|(peer, nick)| info!("{} -> {}", peer, nick));
+}
+
+async fn handle_nickname(cmd: &str) {
+    let rest = match cmd.strip_prefix("nick ") {
+        Some(rest) => rest.trim(),
+        None => return,
+    };
+    let (peer, nick) = match rest.strip_prefix("clear ") {
+        Some(peer) => (peer.trim(), None),
+        None => {
+            match rest.split_once(' ') {
+                Some((peer, nick)) if !nick.trim().is_empty() => (peer, Some(nick.trim())),
+                _ => {
+                    info!("invalid arguments - Format: nick <peer_id> <nickname> | nick clear <peer_id>");
+                    return;
+                }
+            }
+        }
+    };
+    if let Err(e) = peer.parse::<PeerId>() {
+        error!("invalid peer id: {}, {}", peer, e);
+        return;
+    }
+    let mut nicknames = NICKNAMES.lock().expect("can lock nicknames").clone();
+    match nick {
+        Some(nick) => {
+            nicknames.insert(peer.to_owned(), nick.to_owned());
+        }
+        None => {
+            nicknames.remove(peer);
+        }
+    }
+    if let Err(e) = write_nicknames(&nicknames).await {
+        error!("error saving nicknames: {}", e);
+        return;
+    }
+    *NICKNAMES.lock().expect("can lock nicknames") = nicknames;
+    if let Some(nick) = nick {
+        info!("{} is now shown as {}", peer, nick);
+    } else {
+        info!("Cleared nickname for {}", peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::core::transport::MemoryTransport;
+    use std::path::PathBuf;
+
+    // storage paths are read once per process, so every test shares this directory
+    static TEST_DIR: Lazy<PathBuf> = Lazy::new(|| {
+        let dir = env::temp_dir().join(format!("p2p-play-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        env::set_var("P2P_PLAY_DB_PATH", dir.join("stories.json"));
+        env::set_var("P2P_PLAY_TOPICS_PATH", dir.join("topics.json"));
+        env::set_var("P2P_PLAY_ALIASES_PATH", dir.join("aliases.json"));
+        env::set_var("P2P_PLAY_NICKNAMES_PATH", dir.join("nicknames.json"));
+        dir
+    });
+    static STORAGE_LOCK: Lazy<tokio::sync::Mutex<()>> = Lazy::new(|| tokio::sync::Mutex::new(()));
+
+    // tests that touch the files in TEST_DIR hold this guard and start from an empty directory
+    async fn fresh_storage() -> tokio::sync::MutexGuard<'static, ()> {
+        let guard = STORAGE_LOCK.lock().await;
+        let _ = std::fs::remove_dir_all(&*TEST_DIR);
+        std::fs::create_dir_all(&*TEST_DIR).unwrap();
+        guard
+    }
+
+    async fn replay_events(
+        path: &Path,
+        swarm: &mut Swarm<StoryBehaviour>,
+        response_sender: &mpsc::UnboundedSender<ListResponse>,
+    ) -> Result<()> {
+        let content = fs::read_to_string(path).await?;
+        for line in content.lines() {
+            match serde_json::from_str::<RecordedEvent>(line)? {
+                RecordedEvent::Input(line) => {
+                    if !handle_input_event(&line, swarm).await {
+                        break;
+                    }
+                }
+                RecordedEvent::PubsubMessage {
+                    source,
+                    topics,
+                    data,
+                } => {
+                    let msg = PubsubMessage {
+                        source: source.parse()?,
+                        topics,
+                        data,
+                    };
+                    handle_pubsub_message(msg, response_sender).await
+                }
+                RecordedEvent::Summary { .. } => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn story(id: usize, name: &str) -> Story {
+        Story {
+            id,
+            name: name.to_owned(),
+            header: "header".to_owned(),
+            body: "body".to_owned(),
+            public: true,
+            favorite: false,
+            reply_to: None,
+            reply_to_title: None,
+        }
+    }
+
+    fn ids(stories: &[Story]) -> Vec<usize> {
+        stories.iter().map(|r| r.id).collect()
+    }
+
+    #[test]
+    fn public_stories_drop_favorite_flag() {
+        let mut favorite = story(0, "a");
+        favorite.favorite = true;
+        let shared = public_stories(vec![favorite]);
+        assert_eq!(ids(&shared), vec![0]);
+        assert!(!shared[0].favorite);
+    }
+
+    #[test]
+    fn command_args_needs_word_boundary() {
+        assert_eq!(command_args("sub t news", "sub t"), Some(" news"));
+        assert_eq!(command_args("sub t", "sub t"), Some(""));
+        assert_eq!(command_args("sub topic", "sub t"), None);
+        assert_eq!(command_args("unsub t news", "sub t"), None);
+    }
+
+    #[tokio::test]
+    async fn dir_writable_probes_the_directory() {
+        let _storage = fresh_storage().await;
+        assert!(dir_writable(&TEST_DIR).await);
+        assert!(!dir_writable(&TEST_DIR.join("missing")).await);
+        assert!(!dir_writable(Path::new("/proc")).await);
+    }
+
+    fn response(data: Stories) -> ListResponse {
+        ListResponse {
+            mode: ListMode::ALL,
+            receiver: "receiver".to_owned(),
+            data,
+        }
+    }
+
+    fn chunk_ids(chunk: &str) -> Vec<usize> {
+        let resp: ListResponse = serde_json::from_str(chunk).unwrap();
+        ids(&resp.data)
+    }
+
+    // a story whose response serializes to exactly `size` bytes
+    fn story_of_size(id: usize, size: usize) -> Story {
+        let empty = || {
+            let mut r = story(id, "s");
+            r.body.clear();
+            r
+        };
+        let base = serde_json::to_string(&response(vec![empty()]))
+            .unwrap()
+            .len();
+        let mut r = empty();
+        r.body = "x".repeat(size - base);
+        r
+    }
+
+    #[test]
+    fn split_response_keeps_story_at_limit() {
+        let chunks = split_response(response(vec![story_of_size(0, MAX_BROADCAST_SIZE)]));
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].len(), MAX_BROADCAST_SIZE);
+        assert_eq!(chunk_ids(&chunks[0]), vec![0]);
+    }
+
+    #[test]
+    fn split_response_skips_story_over_limit() {
+        let chunks = split_response(response(vec![story_of_size(0, MAX_BROADCAST_SIZE + 1)]));
+        assert_eq!(chunks.len(), 1);
+        assert!(chunk_ids(&chunks[0]).is_empty());
+    }
+
+    #[test]
+    fn split_response_splits_several_stories() {
+        let stories = (0..5).map(|id| story_of_size(id, 700)).collect();
+        let chunks = split_response(response(stories));
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|c| c.len() <= MAX_BROADCAST_SIZE));
+        let sent: Vec<usize> = chunks.iter().flat_map(|c| chunk_ids(c)).collect();
+        assert_eq!(sent, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn fingerprint_is_deterministic() {
+        let key = identity::Keypair::generate_ed25519().public();
+        let other = identity::Keypair::generate_ed25519().public();
+        assert_eq!(fingerprint(&key), fingerprint(&key.clone()));
+        assert_ne!(fingerprint(&key), fingerprint(&other));
+        let printed = fingerprint(&key);
+        let groups: Vec<&str> = printed.split(' ').collect();
+        assert_eq!(groups.len(), 8);
+        assert!(groups
+            .iter()
+            .all(|group| group.len() == 4 && group.chars().all(|c| c.is_ascii_hexdigit())));
+    }
+
+    #[test]
+    fn public_stories_carry_public_reply_titles() {
+        let mut private_parent = story(1, "private");
+        private_parent.public = false;
+        let mut public_reply = story(2, "reply");
+        public_reply.reply_to = Some(0);
+        let mut private_reply = story(3, "reply");
+        private_reply.reply_to = Some(1);
+        let shared = public_stories(vec![
+            story(0, "parent"),
+            private_parent,
+            public_reply,
+            private_reply,
+        ]);
+        assert_eq!(ids(&shared), vec![0, 2, 3]);
+        assert_eq!(shared[1].reply_to_title.as_deref(), Some("parent"));
+        assert_eq!(shared[2].reply_to_title, None);
+    }
+
+    #[test]
+    fn sanitize_for_display_neutralises_escapes() {
+        let red = sanitize_for_display("\x1b[31mred\x1b[0m");
+        assert_eq!(red, "[31mred[0m");
+        assert!(!sanitize_for_display("\u{9b}2J cleared").contains('\u{9b}'));
+        assert_eq!(sanitize_for_display("harmless\rFORGED"), "harmlessFORGED");
+        assert_eq!(sanitize_for_display("line\nbreak\ttab"), "linebreaktab");
+    }
+
+    #[test]
+    fn parse_visibility_needs_a_separator() {
+        assert_eq!(parse_visibility(" --public a|b|c"), (true, " a|b|c"));
+        assert_eq!(parse_visibility(" --private|a|b|c"), (false, "|a|b|c"));
+        assert_eq!(
+            parse_visibility(" --publicity|b|c"),
+            (*DEFAULT_PUBLIC, " --publicity|b|c")
+        );
+    }
+
+    #[tokio::test]
+    async fn private_stories_are_not_served() {
+        let _storage = fresh_storage().await;
+        let mut private = story(1, "private");
+        private.public = false;
+        write_local_stories(&vec![story(0, "public"), private])
+            .await
+            .unwrap();
+
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        respond_with_public_stories(sender.clone(), "peer".to_owned());
+        let resp = receiver.recv().await.unwrap();
+        assert_eq!(ids(&resp.data), vec![0]);
+
+        respond_with_stories_by_title(sender, "peer".to_owned(), "private".to_owned());
+        assert!(receiver.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn db_check_reports_file_state() {
+        let _storage = fresh_storage().await;
+        assert_eq!(check_stories_file().await.unwrap(), DbCheck::Missing);
+
+        let mut reply = story(1, "reply");
+        reply.reply_to = Some(0);
+        write_local_stories(&vec![story(0, "parent"), reply])
+            .await
+            .unwrap();
+        assert_eq!(check_stories_file().await.unwrap(), DbCheck::Healthy(2));
+
+        let mut dangling = story(2, "dangling");
+        dangling.reply_to = Some(7);
+        write_local_stories(&vec![story(0, "a"), story(0, "b"), dangling])
+            .await
+            .unwrap();
+        assert_eq!(
+            check_stories_file().await.unwrap(),
+            DbCheck::Problems(vec![
+                "duplicate story id 0".to_owned(),
+                "story 2 replies to missing story 7".to_owned(),
+            ])
+        );
+
+        std::fs::write(&*STORAGE_PATH, "not json").unwrap();
+        assert!(check_stories_file().await.is_err());
+    }
+
+    #[test]
+    fn parse_list_args_defaults() {
+        let opts = parse_list_args("").unwrap();
+        assert_eq!(opts.page, 1);
+        assert_eq!(opts.size, *PAGE_SIZE);
+        assert!(matches!(opts.sort, SortMode::Oldest));
+    }
+
+    #[test]
+    fn parse_list_args_reads_flags() {
+        let opts = parse_list_args("--page 3 --size 5 --sort title").unwrap();
+        assert_eq!(opts.page, 3);
+        assert_eq!(opts.size, 5);
+        assert!(matches!(opts.sort, SortMode::Title));
+    }
+
+    #[test]
+    fn parse_list_args_rejects_bad_input() {
+        assert!(parse_list_args("--page 0").is_err());
+        assert!(parse_list_args("--size 0").is_err());
+        assert!(parse_list_args("--page").is_err());
+        assert!(parse_list_args("--page -1").is_err());
+        assert!(parse_list_args("--sort sideways").is_err());
+        assert!(parse_list_args("--colour red").is_err());
+    }
+
+    #[test]
+    fn page_stories_boundary_pages() {
+        let stories = || (0..5).map(|id| story(id, "s")).collect::<Stories>();
+
+        let (all, page) = page_stories(stories(), 0, 2, SortMode::Oldest);
+        assert_eq!((ids(&all[page]), all.len()), (vec![0, 1], 5));
+
+        let (all, page) = page_stories(stories(), 4, 2, SortMode::Oldest);
+        assert_eq!(ids(&all[page]), vec![4]);
+
+        let (all, page) = page_stories(stories(), 5, 2, SortMode::Oldest);
+        assert!(page.is_empty());
+        assert_eq!(all.len(), 5);
+
+        let (_, page) = page_stories(stories(), usize::MAX, usize::MAX, SortMode::Oldest);
+        assert!(page.is_empty());
+    }
+
+    #[test]
+    fn page_stories_sorts() {
+        let stories = || vec![story(0, "b"), story(1, "C"), story(2, "a")];
+
+        let (all, page) = page_stories(stories(), 0, 3, SortMode::Newest);
+        assert_eq!(ids(&all[page]), vec![2, 1, 0]);
+
+        let (all, page) = page_stories(stories(), 0, 3, SortMode::Title);
+        assert_eq!(ids(&all[page]), vec![2, 0, 1]);
+    }
+
+    async fn memory_swarm(pubsub: PubsubKind) -> Swarm<StoryBehaviour> {
+        let keys = identity::Keypair::generate_ed25519();
+        let auth_keys = Keypair::<X25519Spec>::new()
+            .into_authentic(&keys)
+            .expect("can create auth keys");
+        let transp = MemoryTransport::default()
+            .upgrade(upgrade::Version::V1)
+            .authenticate(NoiseConfig::xx(auth_keys).into_authenticated())
+            .multiplex(mplex::MplexConfig::new())
+            .boxed();
+        let behaviour = StoryBehaviour::new(&keys, pubsub).await;
+        SwarmBuilder::new(transp, behaviour, PeerId::from(keys.public()))
+            .executor(Box::new(|fut| {
+                tokio::spawn(fut);
+            }))
+            .build()
+    }
+
+    // two nodes that are connected and know `b` subscribes to `topic`
+    async fn connected_pair(
+        pubsub: PubsubKind,
+        topic: &Topic,
+    ) -> (Swarm<StoryBehaviour>, Swarm<StoryBehaviour>) {
+        let mut a = memory_swarm(pubsub).await;
+        let mut b = memory_swarm(pubsub).await;
+        let b_id = *b.local_peer_id();
+        a.behaviour_mut().subscribe(topic.clone());
+        b.behaviour_mut().subscribe(topic.clone());
+        a.listen_on("/memory/0".parse().unwrap()).unwrap();
+        let addr = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = a.select_next_some().await {
+                break address;
+            }
+        };
+        b.dial(addr).unwrap();
+
+        let connected = async {
+            loop {
+                tokio::select! {
+                    event = a.select_next_some() => match event {
+                        SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                            a.behaviour_mut().add_peer(peer_id)
+                        }
+                        SwarmEvent::Behaviour(
+                            StoryBehaviourEvent::Floodsub(FloodsubEvent::Subscribed { peer_id, topic: t })
+                        ) if peer_id == b_id && t.id() == topic.id() => return,
+                        SwarmEvent::Behaviour(
+                            StoryBehaviourEvent::Gossipsub(GossipsubEvent::Subscribed { peer_id, topic: t })
+                        ) if peer_id == b_id && t.as_str() == topic.id() => return,
+                        _ => {}
+                    },
+                    event = b.select_next_some() => {
+                        if let SwarmEvent::ConnectionEstablished { peer_id, .. } = event {
+                            b.behaviour_mut().add_peer(peer_id)
+                        }
+                    }
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), connected)
+            .await
+            .expect("nodes connect");
+        (a, b)
+    }
+
+    fn as_message<E>(event: SwarmEvent<StoryBehaviourEvent, E>) -> Option<PubsubMessage> {
+        match event {
+            SwarmEvent::Behaviour(StoryBehaviourEvent::Floodsub(FloodsubEvent::Message(msg))) => {
+                Some(msg.into())
+            }
+            SwarmEvent::Behaviour(StoryBehaviourEvent::Gossipsub(GossipsubEvent::Message {
+                propagation_source,
+                message,
+                ..
+            })) => Some(PubsubMessage::from_gossipsub(message, propagation_source)),
+            _ => None,
+        }
+    }
+
+    async fn assert_two_node_delivery(pubsub: PubsubKind) {
+        let (mut a, mut b) = connected_pair(pubsub, &TOPIC).await;
+        let a_id = *a.local_peer_id();
+        a.behaviour_mut().publish(TOPIC.clone(), b"hello");
+
+        let received = async {
+            loop {
+                tokio::select! {
+                    _ = a.select_next_some() => {}
+                    event = b.select_next_some() => {
+                        if let Some(msg) = as_message(event) {
+                            return msg;
+                        }
+                    }
+                }
+            }
+        };
+        let msg = tokio::time::timeout(Duration::from_secs(10), received)
+            .await
+            .expect("message is delivered");
+        assert_eq!(msg.source, a_id);
+        assert_eq!(msg.topics, vec![TOPIC.id().to_owned()]);
+        assert_eq!(msg.data, b"hello");
+    }
+
+    #[tokio::test]
+    async fn two_nodes_exchange_messages_over_floodsub() {
+        assert_two_node_delivery(PubsubKind::Floodsub).await;
+    }
+
+    #[tokio::test]
+    async fn two_nodes_exchange_messages_over_gossipsub() {
+        assert_two_node_delivery(PubsubKind::Gossipsub).await;
+    }
+
+    #[tokio::test]
+    async fn script_sets_up_topic_and_story() {
+        let _storage = fresh_storage().await;
+        let script = TEST_DIR.join("setup.txt");
+        std::fs::write(
+            &script,
+            "# setup for a new node\n\
+             \n\
+             create s --private Script story|A header|A body\n\
+             alias news sub t news\n\
+             news\n\
+             q\n\
+             create s --private Not created|x|y\n",
+        )
+        .unwrap();
+
+        // runs like `p2p-play --script setup.txt < /dev/null`
+        let mut swarm = memory_swarm(PubsubKind::Floodsub).await;
+        let stdin = tokio::io::BufReader::new(&b""[..]).lines();
+        let script = Some(script.to_str().unwrap().to_owned());
+        tokio::time::timeout(
+            SCRIPT_PEER_WAIT + Duration::from_secs(5),
+            run_event_loop(&mut swarm, stdin, script, None),
+        )
+        .await
+        .expect("script runs to quit");
+
+        let stories = read_local_stories().await.unwrap();
+        assert_eq!(stories.len(), 1);
+        assert_eq!(stories[0].name.trim(), "Script story");
+        assert!(!stories[0].public);
+        assert_eq!(read_subscribed_topics().await.unwrap(), vec!["news"]);
+    }
+
+    #[tokio::test]
+    async fn recorded_events_replay() {
+        let _storage = fresh_storage().await;
+        let log = TEST_DIR.join("events.jsonl");
+        let requester = PeerId::random();
+        let request = serde_json::to_vec(&ListRequest {
+            mode: ListMode::ALL,
+        })
+        .unwrap();
+
+        let mut recorder = EventRecorder::open(log.to_str().unwrap()).await.unwrap();
+        let events = [
+            EventType::Input("create s Replayed|header|body".to_owned()),
+            EventType::Input("publish s 0".to_owned()),
+            EventType::PingEvent(ping::Event {
+                peer: requester,
+                result: Ok(ping::Success::Pong),
+            }),
+            EventType::PubsubMessage(PubsubMessage {
+                source: requester,
+                topics: vec![TOPIC.id().to_owned()],
+                data: request,
+            }),
+        ];
+        for event in events.iter() {
+            recorder.record(event).await.unwrap();
+        }
+
+        let content = std::fs::read_to_string(&log).unwrap();
+        let recorded: Vec<RecordedEvent> = content
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(recorded.len(), 4);
+        assert!(matches!(&recorded[2], RecordedEvent::Summary { kind, .. } if kind == "Ping"));
+
+        let mut swarm = memory_swarm(PubsubKind::Floodsub).await;
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        replay_events(&log, &mut swarm, &sender).await.unwrap();
+        let resp = tokio::time::timeout(Duration::from_secs(10), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(resp.receiver, requester.to_string());
+        assert_eq!(ids(&resp.data), vec![0]);
+    }
+
+    #[tokio::test]
+    async fn quit_sends_queued_messages() {
+        let (mut a, mut b) = connected_pair(PubsubKind::Floodsub, &Topic::new("news")).await;
+        let stdin = tokio::io::BufReader::new(&b"pub t news hi\nquit\n"[..]).lines();
+
+        let received = async {
+            loop {
+                if let Some(msg) = as_message(b.select_next_some().await) {
+                    return msg;
+                }
+            }
+        };
+        let (_, msg) = tokio::time::timeout(
+            Duration::from_secs(10),
+            futures::future::join(run_event_loop(&mut a, stdin, None, None), received),
+        )
+        .await
+        .expect("node quits and the message arrives");
+        assert_eq!(msg.topics, vec!["news".to_owned()]);
+        assert_eq!(msg.data, b"hi");
+    }
+
+    #[tokio::test]
+    async fn ping_accepts_a_nickname() {
+        let _storage = fresh_storage().await;
+        let (a, b) = connected_pair(PubsubKind::Floodsub, &TOPIC).await;
+        let b_id = *b.local_peer_id();
+        NICKNAMES
+            .lock()
+            .unwrap()
+            .insert(b_id.to_string(), "bob".to_owned());
+
+        handle_ping("ping", &a);
+        assert!(!take_pending_ping(&b_id));
+        handle_ping("ping bob", &a);
+        assert!(take_pending_ping(&b_id));
+        NICKNAMES.lock().unwrap().clear();
+    }
+
+    #[test]
+    fn builtins_come_from_the_command_table() {
+        assert_eq!(
+            parse_command("sub t news"),
+            Some((Command::Subscribe, " news"))
+        );
+        assert_eq!(parse_command("ls p"), Some((Command::ListPeers, "")));
+        assert_eq!(parse_command("sub topic"), None);
+        for (name, command) in COMMANDS {
+            assert_eq!(parse_command(name), Some((*command, "")));
+            assert!(is_builtin(name.split(' ').next().unwrap()));
+        }
+        assert!(!is_builtin("l"));
+    }
+
+    #[tokio::test]
+    async fn aliases_are_kept_in_memory() {
+        let _storage = fresh_storage().await;
+        handle_define_alias("alias ls sub t news").await;
+        assert_eq!(expand_alias("ls t"), "ls t");
+
+        handle_define_alias("alias sn sub t news").await;
+        assert_eq!(expand_alias("sn"), "sub t news");
+        assert_eq!(read_aliases().await.unwrap()["sn"], "sub t news");
+        assert_eq!(expand_alias("q"), "quit");
     }
 }